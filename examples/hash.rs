@@ -1,11 +1,11 @@
 use chrono;
 use emtm_verify;
-use emtm_verify::Verifier;
+use emtm_verify::TencentOcr;
 use rand::random;
 use std::collections::BTreeMap;
 
 fn main() {
-    let v = Verifier::new();
+    let ocr = TencentOcr::from_env();
     let params = {
         let mut map = BTreeMap::new();
         map.insert("app_id", 1000001.to_string());
@@ -24,5 +24,5 @@ fn main() {
         );
         map
     };
-    println!("{}", v.get_sign_hash(&params));
+    println!("{}", ocr.get_sign_hash(&params));
 }
@@ -0,0 +1,323 @@
+//! Content-addressed caching of verification outcomes.
+//!
+//! Keys are derived from a digest of the *normalized* request (the
+//! re-encoded JPEG bytes plus the institute/student id arguments), so
+//! repeated verifications of an image we've already OCR'd never hit the
+//! network.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use actix_web::web;
+use futures::Future;
+use hex;
+use lru::LruCache;
+use md5::{Digest, Md5};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::ocr::OcrItem;
+
+/// Digest of a normalized verification request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// Builds a key from the re-encoded JPEG bytes and the verification
+    /// arguments. The JPEG bytes, not the original upload, are hashed so
+    /// that two uploads which resize/encode down to the same image share
+    /// a cache entry.
+    ///
+    /// Each field is hashed behind its own length prefix, and `student_id`
+    /// additionally behind a presence byte, so that concatenation can't
+    /// make two different `(institute, student_id)` pairs collide (e.g.
+    /// `institute="AB", student_id=Some("CD")` vs.
+    /// `institute="ABC", student_id=Some("D")`).
+    pub fn new(jpeg_data: &[u8], institute: &str, student_id: Option<&str>) -> Self {
+        let mut hasher = Md5::new();
+        Self::hash_field(&mut hasher, jpeg_data);
+        Self::hash_field(&mut hasher, institute.as_bytes());
+        match student_id {
+            Some(id) => {
+                hasher.input(&[1u8]);
+                Self::hash_field(&mut hasher, id.as_bytes());
+            }
+            None => hasher.input(&[0u8]),
+        }
+        CacheKey(hex::encode(&hasher.result()[..]))
+    }
+
+    /// Feeds `field` into `hasher` behind its length, so that a hasher fed
+    /// `hash_field(a); hash_field(b)` can never collide with one fed
+    /// `hash_field(a'); hash_field(b')` for different `(a, b)` pairs.
+    fn hash_field(hasher: &mut Md5, field: &[u8]) {
+        hasher.input(&(field.len() as u64).to_le_bytes());
+        hasher.input(field);
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The outcome of a verification, without the transport/parse errors that
+/// are never worth caching (those depend on the API server, not the
+/// image content).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CachedOutcome {
+    Success,
+    InstituteNotMatch { best_score: f64 },
+    StudentIdNotMatch { best_score: f64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedOcrItem {
+    pub item: String,
+    pub itemstring: String,
+}
+
+impl From<&OcrItem> for CachedOcrItem {
+    fn from(item: &OcrItem) -> Self {
+        CachedOcrItem {
+            item: item.item.clone(),
+            itemstring: item.itemstring.clone(),
+        }
+    }
+}
+
+/// The cached result of a previous `Verifier::verify` call: the outcome
+/// plus the OCR `item_list` it was computed from, in case a caller wants
+/// to re-derive a match score without another OCR round trip.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedVerification {
+    pub outcome: CachedOutcome,
+    pub item_list: Vec<CachedOcrItem>,
+}
+
+struct CacheEntry {
+    value: CachedVerification,
+    expires_at: SystemTime,
+}
+
+/// A pluggable store for `CachedVerification` results, keyed by
+/// `CacheKey`. Implementations are responsible for their own expiry
+/// bookkeeping based on the `ttl` passed to `put`.
+///
+/// `get`/`put` are polled from `Verifier::verify`'s future chain, which
+/// runs on the reactor — so implementations backed by blocking I/O (see
+/// `SqliteCache`) must offload that work themselves (e.g. via
+/// `actix_web::web::block`) rather than return a future that blocks the
+/// thread it's polled on. Both methods swallow store-internal errors
+/// (a cache miss and a cache error are indistinguishable to the caller)
+/// so the returned futures never fail.
+pub trait CacheStore: Send + Sync {
+    fn get(&self, key: &CacheKey) -> Box<Future<Item = Option<CachedVerification>, Error = ()> + Send>;
+    fn put(
+        &self,
+        key: CacheKey,
+        value: CachedVerification,
+        ttl: Duration,
+    ) -> Box<Future<Item = (), Error = ()> + Send>;
+}
+
+/// An in-memory LRU cache. Cheap default for a single process; entries
+/// are lost on restart.
+pub struct InMemoryCache {
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+}
+
+impl InMemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        InMemoryCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl CacheStore for InMemoryCache {
+    fn get(&self, key: &CacheKey) -> Box<Future<Item = Option<CachedVerification>, Error = ()> + Send> {
+        let mut entries = self.entries.lock().unwrap();
+        let found = match entries.get(key) {
+            Some(entry) if entry.expires_at > SystemTime::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        // A mutex-guarded in-memory lookup is cheap enough to finish
+        // before this future is ever polled, so there's nothing to
+        // offload: wrap the already-computed result in `future::ok`.
+        Box::new(futures::future::ok(found))
+    }
+
+    fn put(
+        &self,
+        key: CacheKey,
+        value: CachedVerification,
+        ttl: Duration,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.put(
+            key,
+            CacheEntry {
+                value,
+                expires_at: SystemTime::now() + ttl,
+            },
+        );
+        Box::new(futures::future::ok(()))
+    }
+}
+
+/// A SQLite-backed cache for sharing verification results across
+/// processes or surviving restarts.
+///
+/// `get`/`put` do blocking disk I/O (`rusqlite` has no async API), so
+/// the actual queries run inside `actix_web::web::block`, which hands
+/// them to actix's blocking thread pool — the reactor thread that polls
+/// the returned future is never the one that touches the connection.
+pub struct SqliteCache {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteCache {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS verification_cache (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+            params![],
+        )?;
+        Ok(SqliteCache {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+}
+
+impl CacheStore for SqliteCache {
+    fn get(&self, key: &CacheKey) -> Box<Future<Item = Option<CachedVerification>, Error = ()> + Send> {
+        let conn = self.conn.clone();
+        let key = key.clone();
+        Box::new(
+            web::block(move || -> Result<Option<CachedVerification>, ()> {
+                let conn = conn.lock().unwrap();
+                let row: Option<(String, i64)> = conn
+                    .query_row(
+                        "SELECT value, expires_at FROM verification_cache WHERE key = ?1",
+                        params![key.as_str()],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .ok();
+
+                let (value, expires_at) = match row {
+                    Some(row) => row,
+                    None => return Ok(None),
+                };
+                let expires_at = SystemTime::UNIX_EPOCH + Duration::from_secs(expires_at as u64);
+                if expires_at <= SystemTime::now() {
+                    let _ = conn.execute(
+                        "DELETE FROM verification_cache WHERE key = ?1",
+                        params![key.as_str()],
+                    );
+                    return Ok(None);
+                }
+
+                Ok(serde_json::from_str(&value).ok())
+            })
+            .then(|res| Ok(res.unwrap_or(None))),
+        )
+    }
+
+    fn put(
+        &self,
+        key: CacheKey,
+        value: CachedVerification,
+        ttl: Duration,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let conn = self.conn.clone();
+        let expires_at = SystemTime::now() + ttl;
+        let expires_at_secs = expires_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        Box::new(
+            web::block(move || -> Result<(), ()> {
+                let encoded = match serde_json::to_string(&value) {
+                    Ok(encoded) => encoded,
+                    Err(_) => return Ok(()),
+                };
+                let conn = conn.lock().unwrap();
+                let _ = conn.execute(
+                    "INSERT OR REPLACE INTO verification_cache (key, value, expires_at) VALUES (?1, ?2, ?3)",
+                    params![key.as_str(), encoded, expires_at_secs],
+                );
+                Ok(())
+            })
+            .then(|_| Ok(())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(outcome: CachedOutcome) -> CachedVerification {
+        CachedVerification {
+            outcome,
+            item_list: vec![CachedOcrItem {
+                item: "name".to_string(),
+                itemstring: "中山大学".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn in_memory_cache_miss_then_hit() {
+        let cache = InMemoryCache::new(16);
+        let key = CacheKey::new(b"jpeg-bytes", "中山大学", Some("16340025"));
+
+        assert!(cache.get(&key).wait().unwrap().is_none());
+
+        cache
+            .put(
+                key.clone(),
+                sample(CachedOutcome::Success),
+                Duration::from_secs(60),
+            )
+            .wait()
+            .unwrap();
+        let hit = cache.get(&key).wait().unwrap().expect("should be cached");
+        assert_eq!(hit.outcome, CachedOutcome::Success);
+    }
+
+    #[test]
+    fn in_memory_cache_expires() {
+        let cache = InMemoryCache::new(16);
+        let key = CacheKey::new(b"jpeg-bytes", "中山大学", None);
+
+        cache
+            .put(
+                key.clone(),
+                sample(CachedOutcome::InstituteNotMatch { best_score: 0.4 }),
+                Duration::from_millis(0),
+            )
+            .wait()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get(&key).wait().unwrap().is_none());
+    }
+
+    #[test]
+    fn cache_key_is_stable_for_same_input() {
+        let a = CacheKey::new(b"same-bytes", "inst", Some("id"));
+        let b = CacheKey::new(b"same-bytes", "inst", Some("id"));
+        assert_eq!(a, b);
+
+        let c = CacheKey::new(b"same-bytes", "inst", Some("other-id"));
+        assert_ne!(a, c);
+    }
+}
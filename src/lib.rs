@@ -0,0 +1,9 @@
+pub mod cache;
+pub mod credential;
+pub mod matching;
+pub mod ocr;
+pub mod preprocessing;
+pub mod verifier;
+
+pub use ocr::{MockOcr, OcrProvider, TencentOcr};
+pub use verifier::{Verifier, VerifierError};
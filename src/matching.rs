@@ -0,0 +1,188 @@
+//! Normalization-tolerant matching of OCR'd fields against expected
+//! values, so a single misread character or a stray space doesn't fail
+//! an otherwise-correct verification.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Default similarity threshold for institute name fuzzy matching.
+pub const DEFAULT_INSTITUTE_THRESHOLD: f64 = 0.85;
+
+/// Default similarity threshold used only for near-miss reporting on
+/// student ids, whose actual match is exact/substring-based.
+pub const DEFAULT_STUDENT_ID_THRESHOLD: f64 = 1.0;
+
+/// Trims, collapses internal whitespace, applies Unicode NFKC
+/// normalization, and folds full-width digits/letters (and the
+/// ideographic space) to their half-width equivalents.
+pub fn normalize(input: &str) -> String {
+    let collapsed = input.split_whitespace().collect::<Vec<_>>().join(" ");
+    collapsed.nfkc().map(fold_fullwidth).collect()
+}
+
+fn fold_fullwidth(c: char) -> char {
+    match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => std::char::from_u32(c as u32 - 0xFF01 + 0x21).unwrap_or(c),
+        _ => c,
+    }
+}
+
+/// Levenshtein edit distance via the standard dynamic-programming
+/// recurrence, using a rolling two-row buffer.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0usize; b.len() + 1];
+
+    for i in 1..=a.len() {
+        curr[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            curr[j] = (prev[j] + 1).min(curr[j - 1] + 1).min(prev[j - 1] + cost);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+
+    prev[b.len()]
+}
+
+/// `1 - distance / max(len_a, len_b)`, already on normalized strings.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let len_a = a.chars().count();
+    let len_b = b.chars().count();
+    let max_len = len_a.max(len_b);
+    if max_len == 0 {
+        return 1.0;
+    }
+    1.0 - (levenshtein(a, b) as f64 / max_len as f64)
+}
+
+/// Fuzzy-matches `expected` against each OCR `itemstring`, normalizing
+/// both sides first. Returns whether the best match clears `threshold`
+/// and that best score, so callers can report near-misses.
+///
+/// `threshold` is a length-normalized similarity (`1 - distance /
+/// max_len`), which barely tolerates OCR noise on short institute names:
+/// a 4-character name (the overwhelming majority of Chinese university
+/// names, e.g. 中山大学/北京大学) needs an exact match to clear the
+/// default 0.85 threshold, since a single substitution alone drops the
+/// score to 0.75. To still tolerate a single misread character on short
+/// names, any pair with `max_len < 7` also matches when its edit
+/// distance is at most 1, regardless of the length-normalized score.
+pub fn best_institute_match<'a>(
+    item_strings: impl Iterator<Item = &'a str>,
+    expected: &str,
+    threshold: f64,
+) -> (bool, f64) {
+    let expected = normalize(expected);
+    let mut matched = false;
+    let mut best = 0.0_f64;
+
+    for item in item_strings {
+        let item = normalize(item);
+        let distance = levenshtein(&item, &expected);
+        let max_len = item.chars().count().max(expected.chars().count());
+        let score = if max_len == 0 {
+            1.0
+        } else {
+            1.0 - (distance as f64 / max_len as f64)
+        };
+        best = best.max(score);
+        if score >= threshold || (max_len < 7 && distance <= 1) {
+            matched = true;
+        }
+    }
+
+    (matched, best)
+}
+
+/// Matches a student id against each OCR `itemstring`: accepted when
+/// normalized strings are equal, or the expected id appears as a
+/// contiguous substring. Also returns the best similarity score for
+/// debugging near-misses.
+pub fn best_student_id_match<'a>(
+    item_strings: impl Iterator<Item = &'a str>,
+    expected: &str,
+) -> (bool, f64) {
+    let expected = normalize(expected);
+    let mut matched = false;
+    let mut best = 0.0_f64;
+
+    for item in item_strings {
+        let item = normalize(item);
+        if item == expected || item.contains(&expected) {
+            matched = true;
+            best = 1.0;
+            continue;
+        }
+        best = best.max(similarity(&item, &expected));
+    }
+
+    (matched, best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_collapses_whitespace_and_folds_fullwidth() {
+        assert_eq!(normalize("  中山   大学  "), "中山 大学");
+        assert_eq!(normalize("\u{FF11}\u{FF12}\u{FF13}"), "123");
+    }
+
+    #[test]
+    fn levenshtein_matches_known_distances() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("中山大学", "中山大学"), 0);
+        assert_eq!(levenshtein("", "abc"), 3);
+    }
+
+    #[test]
+    fn institute_match_tolerates_single_misread_char_on_short_name() {
+        // "中山大学" is a 4-character name, the common case for Chinese
+        // universities. Length-normalized similarity alone can't tolerate
+        // even one substitution here (distance 1 / max_len 4 = 0.75,
+        // below the 0.85 threshold), so this exercises the short-name
+        // edit-distance fallback instead.
+        let items = vec!["中山大學", "16340025"];
+        let (matched, score) =
+            best_institute_match(items.into_iter(), "中山大学", DEFAULT_INSTITUTE_THRESHOLD);
+        assert!(matched);
+        assert!(score < DEFAULT_INSTITUTE_THRESHOLD);
+    }
+
+    #[test]
+    fn institute_match_tolerates_single_misread_char() {
+        // "中山大学附屬医院" differs from "中山大学附属医院" by one
+        // character (简/繁 variant NFKC doesn't fold). At 8 characters the
+        // length-normalized similarity (0.875) still clears the 0.85
+        // threshold, unlike on a bare 4-character university name.
+        let items = vec!["中山大学附屬医院", "16340025"];
+        let (matched, score) = best_institute_match(
+            items.into_iter(),
+            "中山大学附属医院",
+            DEFAULT_INSTITUTE_THRESHOLD,
+        );
+        assert!(matched);
+        assert!(score > DEFAULT_INSTITUTE_THRESHOLD);
+    }
+
+    #[test]
+    fn institute_match_rejects_unrelated_string() {
+        let items = vec!["某某职业技术学院"];
+        let (matched, _) =
+            best_institute_match(items.into_iter(), "中山大学", DEFAULT_INSTITUTE_THRESHOLD);
+        assert!(!matched);
+    }
+
+    #[test]
+    fn student_id_matches_as_substring() {
+        let items = vec!["学号:16340025"];
+        let (matched, score) = best_student_id_match(items.into_iter(), "16340025");
+        assert!(matched);
+        assert_eq!(score, 1.0);
+    }
+}
@@ -0,0 +1,262 @@
+//! OCR backends. `Verifier` drives institute/student-id matching against
+//! whatever `OcrProvider` it's given, so the matching logic in
+//! `verifier::Verifier` runs identically regardless of which OCR service
+//! produced the `OcrItem`s.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration;
+
+use actix_web::client::{Client, SendRequestError};
+use futures::{Future, Stream};
+use hex;
+use log::*;
+use md5::{Digest, Md5};
+use rand::random;
+use serde::*;
+
+use crate::verifier::VerifierError;
+
+/// A single recognized text field, e.g. the institute name or student id
+/// printed on a card.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OcrItem {
+    pub item: String,
+    pub itemstring: String,
+}
+
+/// Recognizes text fields from a JPEG-encoded image.
+pub trait OcrProvider: Send + Sync {
+    fn recognize(&self, jpeg: &[u8]) -> Box<Future<Item = Vec<OcrItem>, Error = VerifierError>>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RequestForm {
+    pub app_id: String,
+    pub time_stamp: String,
+    pub nonce_str: String,
+    pub image: String,
+    pub sign: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ResponseData {
+    pub angle: String,
+    pub item_list: Vec<OcrItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ResponseParams {
+    pub ret: u64,
+    pub msg: String,
+    pub data: ResponseData,
+}
+
+static OCR_URL: &str = "https://api.ai.qq.com/fcgi-bin/ocr/ocr_bcocr";
+
+/// Default request timeout for the Tencent OCR API call.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Default cap on the API response body, beyond which the request aborts
+/// rather than buffering unbounded data.
+const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+/// The production `OcrProvider`, backed by the Tencent `ocr_bcocr` API.
+#[derive(Clone)]
+pub struct TencentOcr {
+    app_id: u64,
+    app_key: String,
+    timeout: Duration,
+    max_body_size: usize,
+}
+
+impl TencentOcr {
+    pub fn new(app_id: u64, app_key: String) -> Self {
+        TencentOcr {
+            app_id,
+            app_key,
+            timeout: DEFAULT_TIMEOUT,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// Reads `TENCENT_APP_ID`/`TENCENT_APP_KEY` (via `.env` if present).
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        openssl_probe::init_ssl_cert_env_vars();
+        let app_id_str = env::var("TENCENT_APP_ID").expect("TENCENT_APP_ID must be set.");
+        let app_id = app_id_str
+            .parse::<u64>()
+            .expect("TENCENT_APP_ID must be an integer");
+        let app_key = env::var("TENCENT_APP_KEY").expect("TENCENT_APP_KEY must be set.");
+        Self::new(app_id, app_key)
+    }
+
+    /// Overrides how long a request waits for the OCR API to respond
+    /// before failing with `VerifierError::Timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Overrides the maximum OCR API response body size that will be
+    /// buffered before failing with `VerifierError::ResponseTooLarge`.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn get_sign_hash(&self, params: &BTreeMap<&str, String>) -> String {
+        let mut encoded = vec![];
+        for (key, value) in params {
+            encoded.push([*key, value].join("="));
+        }
+        encoded.push(["app_key", &self.app_key].join("="));
+        let to_hash = encoded.join("&");
+        debug!("to_hash: {}", to_hash);
+        let mut hasher = Md5::new();
+        hasher.input(to_hash);
+
+        hex::encode(&hasher.result()[..]).to_ascii_uppercase()
+    }
+
+    fn api_request(
+        form: &RequestForm,
+        timeout: Duration,
+        max_body_size: usize,
+    ) -> Box<Future<Item = String, Error = VerifierError>> {
+        let mut client_builder = Client::build();
+        client_builder = client_builder.timeout(timeout);
+        let client = client_builder.finish();
+
+        let ret = client
+            .post(OCR_URL)
+            .set_header("Content-Type", "application/x-www-form-urlencoded")
+            .send_form(form)
+            .map_err(|error| {
+                warn!("Error {:?} when requesting api", error);
+                match error {
+                    SendRequestError::Timeout => VerifierError::Timeout,
+                    other => VerifierError::ApiServerConnectionError(other),
+                }
+            })
+            .and_then(move |response| {
+                debug!("Response header: {:?}", response);
+                use actix_web::http::StatusCode;
+                match response.status() {
+                    StatusCode::OK => {
+                        let body = response
+                            .map_err(|e| VerifierError::ServerResponseError(e.to_string()))
+                            .fold(Vec::new(), move |mut acc, chunk| {
+                                if acc.len() + chunk.len() > max_body_size {
+                                    return Err(VerifierError::ResponseTooLarge);
+                                }
+                                acc.extend_from_slice(&chunk);
+                                Ok(acc)
+                            })
+                            .map(|buf| String::from_utf8_lossy(&buf).into_owned());
+                        Box::new(body) as Box<Future<Item = String, Error = VerifierError>>
+                    }
+                    status => Box::new(futures::future::err(VerifierError::ApiServerError(
+                        format!("Server response code {}", status),
+                    ))),
+                }
+            });
+        Box::new(ret)
+    }
+}
+
+impl OcrProvider for TencentOcr {
+    fn recognize(&self, jpeg: &[u8]) -> Box<Future<Item = Vec<OcrItem>, Error = VerifierError>> {
+        let base64_image = base64::encode(jpeg);
+
+        let mut params = {
+            let mut map = BTreeMap::new();
+            map.insert("app_id", self.app_id.to_string());
+            map.insert("time_stamp", chrono::Utc::now().timestamp().to_string());
+            map.insert(
+                "nonce_str",
+                (0..30)
+                    .map(|_| ('a' as u8 + (random::<f32>() * 26.0) as u8) as char)
+                    .collect(),
+            );
+            map.insert(
+                "image",
+                //To URL encoding
+                base64_image
+                    .replace("=", "%3D")
+                    .replace("+", "%2B")
+                    .replace("/", "%2F"),
+            );
+            map
+        };
+
+        let md5_hash = self.get_sign_hash(&params);
+        debug!("hashed: {}", md5_hash);
+        let form = RequestForm {
+            app_id: params.remove("app_id").unwrap(),
+            time_stamp: params.remove("time_stamp").unwrap(),
+            nonce_str: params.remove("nonce_str").unwrap(),
+            image: base64_image,
+            sign: md5_hash,
+        };
+
+        let ret =
+            Self::api_request(&form, self.timeout, self.max_body_size).and_then(|api_response| {
+                debug!("response: {}", api_response);
+
+                let ocr_result: ResponseParams = match serde_json::from_str(&api_response) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        debug!("failed to parse json: {}", e);
+                        return Err(VerifierError::ApiServerError(
+                            "Failed to parse API server response.".to_string(),
+                        ));
+                    }
+                };
+
+                if ocr_result.ret != 0 {
+                    return Err(VerifierError::ServerResponseError(ocr_result.msg));
+                }
+
+                Ok(ocr_result.data.item_list)
+            });
+        Box::new(ret)
+    }
+}
+
+/// An `OcrProvider` that returns a fixed item list, for exercising the
+/// matching logic in tests without network access or credentials.
+pub struct MockOcr {
+    items: Vec<OcrItem>,
+}
+
+impl MockOcr {
+    pub fn new(items: Vec<OcrItem>) -> Self {
+        MockOcr { items }
+    }
+}
+
+impl OcrProvider for MockOcr {
+    fn recognize(&self, _jpeg: &[u8]) -> Box<Future<Item = Vec<OcrItem>, Error = VerifierError>> {
+        Box::new(futures::future::ok(self.items.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn mock_ocr_returns_configured_items() {
+        let mock = MockOcr::new(vec![OcrItem {
+            item: "name".to_string(),
+            itemstring: "中山大学".to_string(),
+        }]);
+
+        let items = mock.recognize(b"ignored").wait().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].itemstring, "中山大学");
+    }
+}
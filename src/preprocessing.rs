@@ -0,0 +1,168 @@
+//! Image preprocessing applied before the resize/JPEG-encode step in
+//! `Verifier::verify`, to improve OCR accuracy on phone photos of
+//! student cards.
+
+use image::DynamicImage;
+use log::warn;
+
+/// A single preprocessing transform. Steps run in the order given to
+/// `Verifier::with_preprocessing`.
+#[derive(Debug, Clone, Copy)]
+pub enum PreprocessStep {
+    /// Reads the EXIF orientation tag (if any) and rotates/flips the
+    /// image upright. Falls back to the original image if the EXIF data
+    /// is missing or fails to parse.
+    AutoOrient,
+    /// Converts the image to grayscale.
+    Grayscale,
+    /// Adjusts contrast by the given factor (`image::DynamicImage::adjust_contrast`).
+    Contrast(f32),
+}
+
+/// Runs `steps` over `img` in order. `original_bytes` is the undecoded
+/// image, needed for `AutoOrient` to read EXIF metadata.
+pub fn apply(img: DynamicImage, original_bytes: &[u8], steps: &[PreprocessStep]) -> DynamicImage {
+    let mut img = img;
+    for step in steps {
+        img = match step {
+            PreprocessStep::AutoOrient => auto_orient(img, original_bytes),
+            PreprocessStep::Grayscale => img.grayscale(),
+            PreprocessStep::Contrast(factor) => img.adjust_contrast(*factor),
+        };
+    }
+    img
+}
+
+fn auto_orient(img: DynamicImage, original_bytes: &[u8]) -> DynamicImage {
+    match read_exif_orientation(original_bytes) {
+        Ok(orientation) => orient(img, orientation),
+        Err(e) => {
+            warn!("Failed to read EXIF orientation, using image as-is: {}", e);
+            img
+        }
+    }
+}
+
+fn read_exif_orientation(data: &[u8]) -> Result<u32, exif::Error> {
+    let mut cursor = std::io::Cursor::new(data);
+    let exif = exif::Reader::new().read_from_container(&mut cursor)?;
+    let orientation = exif
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1);
+    Ok(orientation)
+}
+
+/// Applies the EXIF orientation transform (1-8, per the TIFF/EXIF spec)
+/// to rotate/flip the image upright.
+fn orient(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            width,
+            height,
+            image::Rgb([120, 130, 140]),
+        ))
+    }
+
+    #[test]
+    fn auto_orient_falls_back_without_exif() {
+        let img = solid_image(4, 2);
+        let processed = apply(
+            img.clone(),
+            b"not a real image",
+            &[PreprocessStep::AutoOrient],
+        );
+        assert_eq!(processed.dimensions(), img.dimensions());
+    }
+
+    /// Builds a minimal raw TIFF/EXIF blob (as `read_from_container` can
+    /// parse directly, without a surrounding JPEG) carrying a single
+    /// `Orientation` field, so `auto_orient` can be exercised without a
+    /// real photo fixture.
+    fn exif_blob_with_orientation(orientation: u16) -> Vec<u8> {
+        let field = exif::Field {
+            tag: exif::Tag::Orientation,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Short(vec![orientation]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&field);
+        let mut buf = std::io::Cursor::new(Vec::new());
+        writer.write(&mut buf, false).unwrap();
+        buf.into_inner()
+    }
+
+    /// A 2x1 image with distinct corner pixels, so rotation/flip can be
+    /// told apart from a no-op.
+    fn marker_image() -> DynamicImage {
+        let mut img = image::RgbImage::from_pixel(2, 1, image::Rgb([0, 0, 0]));
+        img.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        img.put_pixel(1, 0, image::Rgb([0, 255, 0]));
+        DynamicImage::ImageRgb8(img)
+    }
+
+    #[test]
+    fn auto_orient_rotates_90_for_orientation_6() {
+        let img = marker_image();
+        let exif = exif_blob_with_orientation(6);
+        let processed = apply(img.clone(), &exif, &[PreprocessStep::AutoOrient]);
+
+        assert_eq!(processed.dimensions(), (1, 2));
+        let expected = img.rotate90();
+        assert_eq!(processed.raw_pixels(), expected.raw_pixels());
+    }
+
+    #[test]
+    fn auto_orient_rotates_180_for_orientation_3() {
+        let img = marker_image();
+        let exif = exif_blob_with_orientation(3);
+        let processed = apply(img.clone(), &exif, &[PreprocessStep::AutoOrient]);
+
+        assert_eq!(processed.dimensions(), img.dimensions());
+        let expected = img.rotate180();
+        assert_eq!(processed.raw_pixels(), expected.raw_pixels());
+    }
+
+    #[test]
+    fn auto_orient_rotates_270_for_orientation_8() {
+        let img = marker_image();
+        let exif = exif_blob_with_orientation(8);
+        let processed = apply(img.clone(), &exif, &[PreprocessStep::AutoOrient]);
+
+        assert_eq!(processed.dimensions(), (1, 2));
+        let expected = img.rotate270();
+        assert_eq!(processed.raw_pixels(), expected.raw_pixels());
+    }
+
+    #[test]
+    fn grayscale_step_converts_color() {
+        let img = solid_image(2, 2);
+        let processed = apply(img, &[], &[PreprocessStep::Grayscale]);
+        let pixel = processed.to_rgb().get_pixel(0, 0).0;
+        assert_eq!(pixel[0], pixel[1]);
+        assert_eq!(pixel[1], pixel[2]);
+    }
+
+    #[test]
+    fn empty_pipeline_is_identity() {
+        let img = solid_image(3, 3);
+        let processed = apply(img.clone(), &[], &[]);
+        assert_eq!(processed.raw_pixels(), img.raw_pixels());
+    }
+}
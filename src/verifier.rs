@@ -1,93 +1,214 @@
-use dotenv::dotenv;
-use hex;
 use image::{GenericImageView, ImageError};
-use md5::{Digest, Md5};
-use rand::random;
-use std::collections::BTreeMap;
-use std::env;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::client::{Client, SendRequestError};
+use futures::sync::oneshot;
 use futures::Future;
 
 use log::*;
-use serde::*;
+
+use crate::cache::{CacheKey, CacheStore, CachedOcrItem, CachedOutcome, CachedVerification};
+use crate::credential::{Claims, CredentialIssuer, SigningKey};
+use crate::matching::{self, DEFAULT_INSTITUTE_THRESHOLD, DEFAULT_STUDENT_ID_THRESHOLD};
+use crate::ocr::{OcrItem, OcrProvider, TencentOcr};
+use crate::preprocessing::{self, PreprocessStep};
+
+/// Default time a cached verification result stays valid for.
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub struct Verifier {
-    app_id: u64,
-    app_key: String,
+    ocr_provider: OcrConfig,
+    cache: Option<Arc<dyn CacheStore>>,
+    cache_ttl: Duration,
+    credential_issuer: Option<Arc<CredentialIssuer>>,
+    preprocessing: Vec<PreprocessStep>,
+    institute_threshold: f64,
+    student_id_threshold: f64,
+}
+
+/// The configured `OcrProvider`, kept as the concrete `TencentOcr` for as
+/// long as possible so that `with_timeout`/`with_max_body_size` can still
+/// reach it, rather than eagerly erasing it behind `Arc<dyn OcrProvider>`.
+enum OcrConfig {
+    Tencent(TencentOcr),
+    Custom(Arc<dyn OcrProvider>),
+}
+
+impl OcrConfig {
+    fn provider(&self) -> Arc<dyn OcrProvider> {
+        match self {
+            OcrConfig::Tencent(tencent) => Arc::new(tencent.clone()),
+            OcrConfig::Custom(provider) => provider.clone(),
+        }
+    }
+}
+
+/// A handle for cancelling an in-flight `verify_cancellable` call.
+/// Dropping the handle (or calling `abort`) cancels the request.
+pub struct AbortHandle {
+    cancel_tx: oneshot::Sender<()>,
+}
+
+impl AbortHandle {
+    pub fn abort(self) {
+        let _ = self.cancel_tx.send(());
+    }
 }
 
 #[derive(Debug)]
 pub enum VerifierError {
-    /// Verification info doesn't match
-    StudentIdNotMatch,
-    InstituteNotMatch,
+    /// Verification info doesn't match; `best_score` is the best
+    /// normalized similarity found across the OCR'd fields, for
+    /// debugging near-misses
+    StudentIdNotMatch {
+        best_score: f64,
+    },
+    InstituteNotMatch {
+        best_score: f64,
+    },
     /// Failed to process image data
     ImageDataError(ImageError),
     /// Failed to encode image data
     JpegEncodeError(std::io::Error),
     /// Failed to connect to api server
-    ApiServerConnectionError(SendRequestError),
+    ApiServerConnectionError(actix_web::client::SendRequestError),
     /// Server returns error message
     ServerResponseError(String),
     /// Api server internal error
     ApiServerError(String),
+    /// The request to the API server did not complete within the
+    /// configured timeout
+    Timeout,
+    /// The API server response body exceeded the configured maximum size
+    ResponseTooLarge,
+    /// The caller aborted an in-flight `verify_cancellable` call
+    Cancelled,
+    /// Failed to sign or verify a credential
+    JwtError(crate::credential::JwtError),
+    /// Failed to load a credential signing/verification key
+    KeyLoadError(crate::credential::KeyLoadError),
+    /// `verify_and_issue`/`validate_credential` called without a
+    /// credential issuer configured via `with_credential_issuer`
+    CredentialIssuerNotConfigured,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct RequestForm {
-    pub app_id: String,
-    pub time_stamp: String,
-    pub nonce_str: String,
-    pub image: String,
-    pub sign: String,
+fn outcome_to_result(outcome: CachedOutcome) -> Result<(), VerifierError> {
+    match outcome {
+        CachedOutcome::Success => Ok(()),
+        CachedOutcome::InstituteNotMatch { best_score } => {
+            Err(VerifierError::InstituteNotMatch { best_score })
+        }
+        CachedOutcome::StudentIdNotMatch { best_score } => {
+            Err(VerifierError::StudentIdNotMatch { best_score })
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct OcrItem {
-    pub item: String,
-    pub itemstring: String,
-}
+impl Verifier {
+    /// Defaults to `TencentOcr`, configured from `TENCENT_APP_ID`/
+    /// `TENCENT_APP_KEY`. Use `with_provider` to supply a different
+    /// `OcrProvider` (e.g. `MockOcr` in tests).
+    pub fn new() -> Self {
+        Self {
+            ocr_provider: OcrConfig::Tencent(TencentOcr::from_env()),
+            cache: None,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            credential_issuer: None,
+            preprocessing: Vec::new(),
+            institute_threshold: DEFAULT_INSTITUTE_THRESHOLD,
+            student_id_threshold: DEFAULT_STUDENT_ID_THRESHOLD,
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ResponseData {
-    pub angle: String,
-    pub item_list: Vec<OcrItem>,
-}
+    /// Builds a `Verifier` backed by the given `OcrProvider`, so the
+    /// institute/student-id matching logic can be exercised without
+    /// network access or credentials (see `ocr::MockOcr`).
+    ///
+    /// `with_timeout`/`with_max_body_size` have no effect on a `Verifier`
+    /// built this way — those settings only apply to the default
+    /// `TencentOcr` provider used by `Verifier::new`.
+    pub fn with_provider(ocr_provider: Arc<dyn OcrProvider>) -> Self {
+        Self {
+            ocr_provider: OcrConfig::Custom(ocr_provider),
+            cache: None,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            credential_issuer: None,
+            preprocessing: Vec::new(),
+            institute_threshold: DEFAULT_INSTITUTE_THRESHOLD,
+            student_id_threshold: DEFAULT_STUDENT_ID_THRESHOLD,
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ResponseParams {
-    pub ret: u64,
-    pub msg: String,
-    pub data: ResponseData,
-}
+    /// Enables content-addressed caching of verification results using
+    /// `store`, so repeated verifications of an already-seen image skip
+    /// the OCR call entirely. Entries expire after `ttl`.
+    pub fn with_cache(mut self, store: Arc<dyn CacheStore>, ttl: Duration) -> Self {
+        self.cache = Some(store);
+        self.cache_ttl = ttl;
+        self
+    }
 
-static OCR_URL: &str = "https://api.ai.qq.com/fcgi-bin/ocr/ocr_bcocr";
+    /// Enables issuance and validation of signed verifiable credentials
+    /// via `verify_and_issue`/`validate_credential`.
+    pub fn with_credential_issuer(mut self, issuer: CredentialIssuer) -> Self {
+        self.credential_issuer = Some(Arc::new(issuer));
+        self
+    }
 
-impl Verifier {
-    pub fn new() -> Self {
-        dotenv().ok();
-        openssl_probe::init_ssl_cert_env_vars();
-        let app_id_str = env::var("TENCENT_APP_ID").expect("TENCENT_APP_ID must be set.");
-        let app_id = app_id_str
-            .parse::<u64>()
-            .expect("TENCENT_APP_ID must be an integer");
-        let app_key = env::var("TENCENT_APP_KEY").expect("TENCENT_APP_KEY must be set.");
-        Self { app_id, app_key }
-    }
-
-    pub fn get_sign_hash(&self, params: &BTreeMap<&str, String>) -> String {
-        let mut encoded = vec![];
-        for (key, value) in params {
-            encoded.push([*key, value].join("="));
+    /// Like `with_credential_issuer`, but loads the signing key directly
+    /// from `key_path` (see `SigningKey::from_file`), surfacing a
+    /// failure to read or parse it as `VerifierError::KeyLoadError`.
+    pub fn with_credential_issuer_from_file(
+        self,
+        key_path: impl AsRef<Path>,
+        app_id: String,
+        expiry: Duration,
+    ) -> Result<Self, VerifierError> {
+        let key = SigningKey::from_file(key_path).map_err(VerifierError::KeyLoadError)?;
+        Ok(self.with_credential_issuer(CredentialIssuer::new(key, app_id, expiry)))
+    }
+
+    /// Sets the image preprocessing pipeline run before the resize/JPEG
+    /// encode step in `verify`, e.g.
+    /// `with_preprocessing(vec![AutoOrient, Grayscale, Contrast(1.2)])`.
+    pub fn with_preprocessing(mut self, steps: Vec<PreprocessStep>) -> Self {
+        self.preprocessing = steps;
+        self
+    }
+
+    /// Overrides the minimum normalized similarity (0.0-1.0) an OCR'd
+    /// field must reach to count as a match for the institute name.
+    /// Defaults to `matching::DEFAULT_INSTITUTE_THRESHOLD`.
+    pub fn with_institute_threshold(mut self, threshold: f64) -> Self {
+        self.institute_threshold = threshold;
+        self
+    }
+
+    /// Overrides the similarity threshold used when reporting a student
+    /// id near-miss. The match itself stays exact/substring-based.
+    pub fn with_student_id_threshold(mut self, threshold: f64) -> Self {
+        self.student_id_threshold = threshold;
+        self
+    }
+
+    /// Forwards to `TencentOcr::with_timeout` on the default provider. No
+    /// effect if a custom `OcrProvider` was supplied via `with_provider`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        if let OcrConfig::Tencent(tencent) = self.ocr_provider {
+            self.ocr_provider = OcrConfig::Tencent(tencent.with_timeout(timeout));
         }
-        encoded.push(["app_key", &self.app_key].join("="));
-        let to_hash = encoded.join("&");
-        debug!("to_hash: {}", to_hash);
-        let mut hasher = Md5::new();
-        hasher.input(to_hash);
+        self
+    }
 
-        hex::encode(&hasher.result()[..]).to_ascii_uppercase()
+    /// Forwards to `TencentOcr::with_max_body_size` on the default
+    /// provider. No effect if a custom `OcrProvider` was supplied via
+    /// `with_provider`.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        if let OcrConfig::Tencent(tencent) = self.ocr_provider {
+            self.ocr_provider = OcrConfig::Tencent(tencent.with_max_body_size(max_body_size));
+        }
+        self
     }
 
     pub fn verify(
@@ -95,6 +216,29 @@ impl Verifier {
         image_data: &[u8],
         institute: &str,
         student_id: Option<&str>,
+    ) -> Box<Future<Item = (), Error = VerifierError>> {
+        self.verify_impl(image_data, institute, student_id, None)
+    }
+
+    /// Like `verify`, but returns an `AbortHandle` the caller can use (or
+    /// drop) to cancel the in-flight OCR request.
+    pub fn verify_cancellable(
+        &self,
+        image_data: &[u8],
+        institute: &str,
+        student_id: Option<&str>,
+    ) -> (AbortHandle, Box<Future<Item = (), Error = VerifierError>>) {
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let fut = self.verify_impl(image_data, institute, student_id, Some(cancel_rx));
+        (AbortHandle { cancel_tx }, fut)
+    }
+
+    fn verify_impl(
+        &self,
+        image_data: &[u8],
+        institute: &str,
+        student_id: Option<&str>,
+        cancel: Option<oneshot::Receiver<()>>,
     ) -> Box<Future<Item = (), Error = VerifierError>> {
         let mut img = match image::load_from_memory(image_data) {
             Ok(img) => img,
@@ -103,6 +247,10 @@ impl Verifier {
             }
         };
 
+        if !self.preprocessing.is_empty() {
+            img = preprocessing::apply(img, image_data, &self.preprocessing);
+        }
+
         // Api only allows image smaller than 1mb
         if image_data.len() > 1048576 {
             info!("Rescale for verification of {}:{:?}", institute, student_id);
@@ -121,114 +269,245 @@ impl Verifier {
         {
             return Box::new(futures::future::err(VerifierError::JpegEncodeError(e)));
         }
-        let base64_image = base64::encode(&jpeg_data);
-
-        let mut params = {
-            let mut map = BTreeMap::new();
-            map.insert("app_id", self.app_id.to_string());
-            map.insert("time_stamp", chrono::Utc::now().timestamp().to_string());
-            map.insert(
-                "nonce_str",
-                (0..30)
-                    .map(|_| ('a' as u8 + (random::<f32>() * 26.0) as u8) as char)
-                    .collect(),
-            );
-            map.insert(
-                "image",
-                //To URL encoding
-                base64_image
-                    .replace("=", "%3D")
-                    .replace("+", "%2B")
-                    .replace("/", "%2F"),
-            );
-            map
-        };
-
-        let md5_hash = self.get_sign_hash(&params);
-        debug!("hashed: {}", md5_hash);
-        let form = RequestForm {
-            app_id: params.remove("app_id").unwrap(),
-            time_stamp: params.remove("time_stamp").unwrap(),
-            nonce_str: params.remove("nonce_str").unwrap(),
-            image: base64_image,
-            sign: md5_hash,
-        };
-
+        let cache_key = CacheKey::new(&jpeg_data, &institute, student_id);
         let sid = match student_id {
             Some(id) => Some(id.to_owned()),
             None => None,
         };
         let institute = institute.to_owned();
+        let cache = self.cache.clone();
+        let cache_ttl = self.cache_ttl;
+        let institute_threshold = self.institute_threshold;
+        let student_id_threshold = self.student_id_threshold;
+        let ocr_provider = self.ocr_provider.provider();
+
+        // `CacheStore::get` returns a future so that a blocking-I/O store
+        // (e.g. `SqliteCache`) can offload the lookup itself instead of
+        // blocking the reactor thread that polls this future.
+        let lookup: Box<Future<Item = Option<CachedVerification>, Error = VerifierError>> =
+            match &cache {
+                Some(store) => Box::new(store.get(&cache_key).then(|res| Ok(res.unwrap_or(None)))),
+                None => Box::new(futures::future::ok(None)),
+            };
+
+        let ret = lookup.and_then(move |cached| -> Box<Future<Item = (), Error = VerifierError>> {
+            if let Some(cached) = cached {
+                info!("Cache hit for verification of {}:{:?}", institute, sid);
+                return Box::new(futures::future::result(outcome_to_result(cached.outcome)));
+            }
 
-        let ret = Self::api_request(&form)
-            .map_err(|err| err)
-            .and_then(move |api_response| {
-                debug!("response: {}", api_response);
-
-                let ocr_result: ResponseParams = match serde_json::from_str(&api_response) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        debug!("failed to parse json: {}", e);
-                        return Err(VerifierError::ApiServerError(
-                            "Failed to parse API server response.".to_string(),
-                        ));
+            let request_future = ocr_provider.recognize(&jpeg_data);
+            let response_future: Box<Future<Item = Vec<OcrItem>, Error = VerifierError>> =
+                match cancel {
+                    Some(cancel_rx) => {
+                        Box::new(request_future.select2(cancel_rx).then(|res| match res {
+                            Ok(futures::future::Either::A((items, _))) => Ok(items),
+                            Ok(futures::future::Either::B((_, _))) => Err(VerifierError::Cancelled),
+                            Err(futures::future::Either::A((err, _))) => Err(err),
+                            Err(futures::future::Either::B((_, _))) => Err(VerifierError::Cancelled),
+                        }))
                     }
+                    None => request_future,
                 };
 
-                if ocr_result.ret != 0 {
-                    return Err(VerifierError::ServerResponseError(ocr_result.msg));
-                }
+            let fut = response_future.and_then(move |item_list| {
+                let item_strings = || item_list.iter().map(|item| item.itemstring.as_str());
 
-                let mut institute_match = false;
-                let mut id_match = sid.is_none();
-                for item in ocr_result.data.item_list {
-                    if item.itemstring == institute {
-                        institute_match = true;
-                    }
-                    if sid.as_ref().is_some() && &item.itemstring == sid.as_ref().unwrap() {
-                        id_match = true;
-                    }
+                let (institute_match, institute_score) = matching::best_institute_match(
+                    item_strings(),
+                    &institute,
+                    institute_threshold,
+                );
+
+                let (id_match, id_score) = match &sid {
+                    Some(id) => matching::best_student_id_match(item_strings(), id),
+                    None => (true, 1.0),
+                };
+                if !id_match && id_score >= student_id_threshold {
+                    debug!(
+                        "Student id near-miss for {}:{:?}, score {} clears threshold {} but match stays exact/substring-based",
+                        institute, sid, id_score, student_id_threshold
+                    );
                 }
 
-                if !institute_match {
-                    Err(VerifierError::InstituteNotMatch)
+                let outcome = if !institute_match {
+                    CachedOutcome::InstituteNotMatch {
+                        best_score: institute_score,
+                    }
                 } else if !id_match {
-                    Err(VerifierError::StudentIdNotMatch)
+                    CachedOutcome::StudentIdNotMatch {
+                        best_score: id_score,
+                    }
                 } else {
-                    Ok(())
-                }
+                    CachedOutcome::Success
+                };
+
+                // Same offloading requirement as `CacheStore::get` above:
+                // `put`'s future is polled here on the reactor.
+                let stored: Box<Future<Item = (), Error = VerifierError>> = match &cache {
+                    Some(store) => {
+                        let cached_item_list = item_list.iter().map(CachedOcrItem::from).collect();
+                        Box::new(
+                            store
+                                .put(
+                                    cache_key,
+                                    CachedVerification {
+                                        outcome,
+                                        item_list: cached_item_list,
+                                    },
+                                    cache_ttl,
+                                )
+                                .then(|_| Ok(())),
+                        )
+                    }
+                    None => Box::new(futures::future::ok(())),
+                };
+
+                stored.and_then(move |()| outcome_to_result(outcome))
             });
+            Box::new(fut)
+        });
         Box::new(ret)
     }
 
-    fn api_request(form: &RequestForm) -> Box<Future<Item = String, Error = VerifierError>> {
-        let mut client_builder = Client::build();
-        //        client_builder = client_builder.timeout(Duration::from_secs(20));
-        client_builder = client_builder.disable_timeout();
-        let client = client_builder.finish();
-
-        let ret = client
-            .post(OCR_URL)
-            .set_header("Content-Type", "application/x-www-form-urlencoded")
-            .send_form(form)
-            .map_err(|error| {
-                warn!("Error {:?} when requesting api", error);
-                VerifierError::ApiServerConnectionError(error)
-            })
-            .and_then(|mut response| {
-                debug!("Response header: {:?}", response);
-                use actix_web::http::StatusCode;
-                match response.status() {
-                    StatusCode::OK => match response.body().wait() {
-                        Ok(item) => Ok(String::from_utf8_lossy(&item[..]).into_owned()),
-                        Err(e) => Err(VerifierError::ServerResponseError(e.to_string())),
-                    },
-                    _ => Err(VerifierError::ApiServerError(format!(
-                        "Server response code {}",
-                        response.status()
-                    ))),
-                }
+    /// Runs `verify` and, on success, issues a signed verifiable
+    /// credential proving the match. Requires a credential issuer
+    /// configured via `with_credential_issuer`.
+    pub fn verify_and_issue(
+        &self,
+        image_data: &[u8],
+        institute: &str,
+        student_id: &str,
+    ) -> Box<Future<Item = String, Error = VerifierError>> {
+        let issuer = match &self.credential_issuer {
+            Some(issuer) => issuer.clone(),
+            None => {
+                return Box::new(futures::future::err(
+                    VerifierError::CredentialIssuerNotConfigured,
+                ));
+            }
+        };
+        let institute = institute.to_owned();
+        let student_id = student_id.to_owned();
+
+        let ret = self
+            .verify(image_data, &institute, Some(&student_id))
+            .and_then(move |()| {
+                issuer
+                    .issue(&institute, &student_id)
+                    .map_err(VerifierError::JwtError)
             });
         Box::new(ret)
     }
+
+    /// Verifies the signature and expiry of a credential previously
+    /// issued by `verify_and_issue`.
+    pub fn validate_credential(&self, token: &str) -> Result<Claims, VerifierError> {
+        match &self.credential_issuer {
+            Some(issuer) => issuer.validate(token).map_err(VerifierError::JwtError),
+            None => Err(VerifierError::CredentialIssuerNotConfigured),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ocr::{MockOcr, OcrItem};
+
+    fn solid_jpeg() -> Vec<u8> {
+        let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            4,
+            4,
+            image::Rgb([120, 130, 140]),
+        ));
+        let mut jpeg_data = vec![];
+        image::jpeg::JPEGEncoder::new(&mut jpeg_data)
+            .encode(&img.raw_pixels(), img.width(), img.height(), img.color())
+            .unwrap();
+        jpeg_data
+    }
+
+    fn mock_verifier(itemstring: &str) -> Verifier {
+        Verifier::with_provider(Arc::new(MockOcr::new(vec![OcrItem {
+            item: "name".to_string(),
+            itemstring: itemstring.to_string(),
+        }])))
+    }
+
+    fn mock_verifier_with_items(items: Vec<(&str, &str)>) -> Verifier {
+        Verifier::with_provider(Arc::new(MockOcr::new(
+            items
+                .into_iter()
+                .map(|(item, itemstring)| OcrItem {
+                    item: item.to_string(),
+                    itemstring: itemstring.to_string(),
+                })
+                .collect(),
+        )))
+    }
+
+    /// An `OcrProvider` whose request never resolves, so tests can drive
+    /// `verify_cancellable`'s abort path without racing a real response.
+    struct PendingOcr;
+
+    impl OcrProvider for PendingOcr {
+        fn recognize(&self, _jpeg: &[u8]) -> Box<Future<Item = Vec<OcrItem>, Error = VerifierError>> {
+            Box::new(futures::future::empty())
+        }
+    }
+
+    #[test]
+    fn verify_succeeds_when_institute_and_student_id_match() {
+        let verifier = mock_verifier_with_items(vec![
+            ("school", "中山大学"),
+            ("student_id", "16340025"),
+        ]);
+        let result = verifier
+            .verify(&solid_jpeg(), "中山大学", Some("16340025"))
+            .wait();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn verify_fails_when_institute_does_not_match() {
+        let verifier = mock_verifier("某某职业技术学院");
+        let result = verifier.verify(&solid_jpeg(), "中山大学", None).wait();
+        assert!(matches!(
+            result,
+            Err(VerifierError::InstituteNotMatch { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_fails_when_student_id_does_not_match() {
+        let verifier = mock_verifier("中山大学");
+        let result = verifier
+            .verify(&solid_jpeg(), "中山大学", Some("16340025"))
+            .wait();
+        assert!(matches!(
+            result,
+            Err(VerifierError::StudentIdNotMatch { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_cancellable_abort_cancels_in_flight_request() {
+        let verifier = Verifier::with_provider(Arc::new(PendingOcr));
+        let (handle, fut) = verifier.verify_cancellable(&solid_jpeg(), "中山大学", None);
+        handle.abort();
+        let result = fut.wait();
+        assert!(matches!(result, Err(VerifierError::Cancelled)));
+    }
+
+    #[test]
+    fn with_credential_issuer_from_file_surfaces_key_load_error() {
+        let result = Verifier::with_provider(Arc::new(MockOcr::new(vec![])))
+            .with_credential_issuer_from_file(
+                "/nonexistent/signing-key.pem",
+                "test-app".to_string(),
+                Duration::from_secs(3600),
+            );
+        assert!(matches!(result, Err(VerifierError::KeyLoadError(_))));
+    }
 }